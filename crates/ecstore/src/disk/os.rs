@@ -17,6 +17,8 @@ use std::{
     path::{Component, Path},
 };
 
+pub use walk_dir::{WalkDir, WalkEntry, walk_dir};
+
 use super::error::Result;
 use crate::disk::error_conv::to_file_error;
 use rustfs_utils::path::SLASH_SEPARATOR;
@@ -77,6 +79,102 @@ pub fn is_root_disk(disk_path: &str, root_disk: &str) -> Result<bool> {
     rustfs_utils::os::same_disk(disk_path, root_disk).map_err(|e| to_file_error(e).into())
 }
 
+/// Coarse classification of the filesystem backing a disk.
+///
+/// `is_root_disk` only tells us disk *identity*; it says nothing about the
+/// underlying filesystem *type*, so NFS-mounted backends are treated
+/// identically to local ext4/xfs even though NFS has materially different
+/// durability and error behavior (transient `ESTALE`/`EIO`, and the usual
+/// mmap/local-cache assumptions don't hold). `reliable_rename` and
+/// `make_dir_all` consult this to choose a more defensive retry/durability
+/// strategy on NFS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Local,
+    Nfs,
+    Tmpfs,
+    Unknown,
+}
+
+#[cfg(target_os = "linux")]
+fn detect_fs_type_uncached(path: &Path) -> FsType {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Magic numbers from `<linux/magic.h>`.
+    const NFS_SUPER_MAGIC: i64 = 0x6969;
+    const TMPFS_MAGIC: i64 = 0x0102_1994;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return FsType::Unknown;
+    };
+
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    // Safety: `c_path` is a valid C string and `buf` is a valid out-pointer.
+    let ret = unsafe { libc::statfs(c_path.as_ptr(), &mut buf) };
+    if ret != 0 {
+        return FsType::Unknown;
+    }
+
+    match buf.f_type as i64 {
+        NFS_SUPER_MAGIC => FsType::Nfs,
+        TMPFS_MAGIC => FsType::Tmpfs,
+        _ => FsType::Local,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn detect_fs_type_uncached(path: &Path) -> FsType {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return FsType::Unknown;
+    };
+
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    // Safety: `c_path` is a valid C string and `buf` is a valid out-pointer.
+    let ret = unsafe { libc::statfs(c_path.as_ptr(), &mut buf) };
+    if ret != 0 {
+        return FsType::Unknown;
+    }
+
+    // Safety: `f_fstypename` is a NUL-terminated array populated by `statfs`.
+    let name = unsafe { std::ffi::CStr::from_ptr(buf.f_fstypename.as_ptr()) }.to_string_lossy();
+    match name.as_ref() {
+        "nfs" => FsType::Nfs,
+        "tmpfs" => FsType::Tmpfs,
+        _ => FsType::Local,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn detect_fs_type_uncached(_path: &Path) -> FsType {
+    FsType::Unknown
+}
+
+static FS_TYPE_CACHE: std::sync::OnceLock<std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, FsType>>> =
+    std::sync::OnceLock::new();
+
+/// Classify the filesystem backing `disk_path`, caching the result per disk.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn detect_fs_type(disk_path: impl AsRef<Path>) -> FsType {
+    if cfg!(target_os = "windows") {
+        return FsType::Unknown;
+    }
+
+    let disk_path = disk_path.as_ref();
+    let cache = FS_TYPE_CACHE.get_or_init(|| std::sync::Mutex::new(std::collections::HashMap::new()));
+
+    if let Some(fs_type) = cache.lock().unwrap().get(disk_path) {
+        return *fs_type;
+    }
+
+    let fs_type = detect_fs_type_uncached(disk_path);
+    cache.lock().unwrap().insert(disk_path.to_path_buf(), fs_type);
+    fs_type
+}
+
 /// Create a directory and all its parent components if they are missing.
 #[tracing::instrument(level = "debug", skip_all)]
 pub async fn make_dir_all(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> Result<()> {
@@ -86,6 +184,12 @@ pub async fn make_dir_all(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) ->
         .await
         .map_err(to_file_error)?;
 
+    // NFS doesn't guarantee a created directory's entry is durable just
+    // because `mkdir` returned success; fsync its parent to close that window.
+    if detect_fs_type(base_dir.as_ref()) == FsType::Nfs {
+        fsync_parent_dir(path.as_ref().to_path_buf()).await.map_err(to_file_error)?;
+    }
+
     Ok(())
 }
 
@@ -96,6 +200,13 @@ pub async fn is_empty_dir(path: impl AsRef<Path>) -> bool {
     read_dir(path.as_ref(), 1).await.is_ok_and(|v| v.is_empty())
 }
 
+/// Returns true if `name` looks like an object data directory: data
+/// directories are named after a UUID, while nested-object directories have
+/// human-readable names that wouldn't parse as one.
+pub fn is_data_dir_name(name: &str) -> bool {
+    uuid::Uuid::parse_str(name).is_ok()
+}
+
 /// Check if an object directory contains subdirectories that may represent nested objects.
 /// Returns true if the directory is "empty" (only contains xl.meta and/or data directories),
 /// meaning no nested object directories exist.
@@ -106,8 +217,6 @@ pub async fn is_empty_dir_except_xlmeta(path: impl AsRef<Path>) -> bool {
     match read_dir(path.as_ref(), 0).await {
         Ok(entries) => {
             // Check if there are any subdirectories that are NOT data directories (UUID-based).
-            // Data directories have names that are valid UUIDs, while nested objects have
-            // human-readable names that wouldn't be valid UUIDs.
             !entries.iter().any(|name| {
                 // Only consider directories (entries ending with /)
                 if !name.ends_with(SLASH_SEPARATOR) {
@@ -115,8 +224,7 @@ pub async fn is_empty_dir_except_xlmeta(path: impl AsRef<Path>) -> bool {
                 }
                 // Get the directory name without trailing slash
                 let dir_name = name.trim_end_matches(SLASH_SEPARATOR);
-                // If it's a valid UUID, it's a data directory, not a nested object
-                uuid::Uuid::parse_str(dir_name).is_err()
+                !is_data_dir_name(dir_name)
             })
         }
         Err(_) => true,
@@ -156,48 +264,113 @@ pub async fn read_dir(path: impl AsRef<Path>, count: i32) -> std::io::Result<Vec
     Ok(volumes)
 }
 
+/// Rename `src_file_path` to `dst_file_path`.
+///
+/// `rename_std` returning success does not guarantee the destination's (and
+/// former source's) directory entry has reached stable storage: on a crash
+/// right after, xl.meta can end up pointing at a missing or duplicated
+/// name. Pass `durable: true` for object-commit paths, where that window
+/// matters; scratch/non-critical moves can pass `false` to skip the extra
+/// fsyncs.
 #[tracing::instrument(level = "debug", skip_all)]
 pub async fn rename_all(
     src_file_path: impl AsRef<Path>,
     dst_file_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
+    durable: bool,
 ) -> Result<()> {
-    reliable_rename(src_file_path, dst_file_path.as_ref(), base_dir)
+    reliable_rename(src_file_path, dst_file_path.as_ref(), base_dir, durable)
         .await
         .map_err(to_file_error)?;
 
     Ok(())
 }
 
+// NFS returns transient ESTALE/EIO far more often than local disks, so
+// rename retry loops get a longer budget there instead of the single
+// retry that's enough to ride out a local filesystem hiccup.
+const LOCAL_RETRY_BUDGET: u32 = 1;
+const NFS_RETRY_BUDGET: u32 = 4;
+
+fn retry_budget_for(base_dir: &Path) -> u32 {
+    if detect_fs_type(base_dir) == FsType::Nfs {
+        NFS_RETRY_BUDGET
+    } else {
+        LOCAL_RETRY_BUDGET
+    }
+}
+
 async fn reliable_rename(
     src_file_path: impl AsRef<Path>,
     dst_file_path: impl AsRef<Path>,
     base_dir: impl AsRef<Path>,
+    durable: bool,
 ) -> io::Result<()> {
-    if let Some(parent) = dst_file_path.as_ref().parent()
+    let base_dir = base_dir.as_ref();
+    let src_file_path = src_file_path.as_ref();
+    let dst_file_path = dst_file_path.as_ref();
+
+    if let Some(parent) = dst_file_path.parent()
         && !file_exists(parent)
     {
         // info!("reliable_rename reliable_mkdir_all parent: {:?}", parent);
-        reliable_mkdir_all(parent, base_dir.as_ref()).await?;
+        reliable_mkdir_all(parent, base_dir).await?;
     }
 
+    let max_retries = retry_budget_for(base_dir);
+    let is_nfs = detect_fs_type(base_dir) == FsType::Nfs;
+
+    #[cfg(unix)]
+    if let Some(result) = dir_handle::reliable_rename_at(src_file_path, dst_file_path, base_dir, max_retries).await {
+        result?;
+        return fsync_after_rename(src_file_path, dst_file_path, durable, is_nfs).await;
+    }
+
+    reliable_rename_path(src_file_path, dst_file_path, base_dir, max_retries).await?;
+    fsync_after_rename(src_file_path, dst_file_path, durable, is_nfs).await
+}
+
+/// fsync the directory entries touched by a rename.
+///
+/// A successful `rename` on NFS doesn't guarantee the destination's entry
+/// update reached stable storage, so that fsync always happens there.
+/// `durable` callers additionally get both the destination's and the
+/// source's parent fsynced on every backend, closing the same crash window
+/// for object-commit renames regardless of filesystem type. No-op on
+/// Windows, where `fsync_parent_dir` is itself a no-op.
+///
+/// `durable` is the exact path this is meant to protect (every object-commit
+/// rename), so both fsyncs run via `fsync_parent_dir`'s blocking-pool thread
+/// rather than synchronously on the async task.
+async fn fsync_after_rename(src_file_path: &Path, dst_file_path: &Path, durable: bool, is_nfs: bool) -> io::Result<()> {
+    if durable {
+        fsync_parent_dir(dst_file_path.to_path_buf()).await?;
+        fsync_parent_dir(src_file_path.to_path_buf()).await?;
+    } else if is_nfs {
+        fsync_parent_dir(dst_file_path.to_path_buf()).await?;
+    }
+    Ok(())
+}
+
+/// Full-path based rename, re-walking `src_file_path`/`dst_file_path` from
+/// the filesystem root on every retry. Used on Windows (no `*at` syscalls)
+/// and as a fallback on Unix when the fast, openat-based path cannot be
+/// used (e.g. the paths are not rooted under `base_dir`).
+async fn reliable_rename_path(src_file_path: &Path, dst_file_path: &Path, base_dir: &Path, max_retries: u32) -> io::Result<()> {
     let mut i = 0;
     loop {
-        if let Err(e) = super::fs::rename_std(src_file_path.as_ref(), dst_file_path.as_ref()) {
+        if let Err(e) = super::fs::rename_std(src_file_path, dst_file_path) {
             if e.kind() == io::ErrorKind::NotFound {
                 break;
             }
 
-            if i == 0 {
+            if i < max_retries {
                 i += 1;
                 continue;
             }
             warn!(
                 "reliable_rename failed. src_file_path: {:?}, dst_file_path: {:?}, base_dir: {:?}, err: {:?}",
-                src_file_path.as_ref(),
-                dst_file_path.as_ref(),
-                base_dir.as_ref(),
-                e
+                src_file_path, dst_file_path, base_dir, e
             );
             return Err(e);
         }
@@ -208,7 +381,43 @@ async fn reliable_rename(
     Ok(())
 }
 
+/// Open and fsync the parent directory of `path`, forcing its directory
+/// entry to be persisted. On NFS a successful `rename` doesn't guarantee the
+/// updated directory entry has reached stable storage, so this is used to
+/// close that window on backends where it matters.
+///
+/// `open`+`fsync` are blocking syscalls, and `fsync` in particular can block
+/// for a full RPC round-trip on NFS, so this runs on a blocking-pool thread
+/// rather than directly on the async task.
+#[cfg(unix)]
+async fn fsync_parent_dir(path: std::path::PathBuf) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let parent = path.parent().unwrap_or(&path);
+        dir_handle::DirHandle::open(parent)?.fsync()
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+#[cfg(not(unix))]
+async fn fsync_parent_dir(_path: std::path::PathBuf) -> io::Result<()> {
+    Ok(())
+}
+
 pub async fn reliable_mkdir_all(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(result) = dir_handle::reliable_mkdir_all_at(path.as_ref(), base_dir.as_ref()).await {
+        return result;
+    }
+
+    reliable_mkdir_all_path(path, base_dir).await
+}
+
+/// Full-path based `mkdir -p`, re-walking the path from the filesystem root
+/// on every retry. Used on Windows (no `*at` syscalls) and as a fallback on
+/// Unix when the fast, openat-based path cannot be used (e.g. `path` is not
+/// rooted under `base_dir`).
+async fn reliable_mkdir_all_path(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> io::Result<()> {
     let mut i = 0;
 
     let mut base_dir = base_dir.as_ref();
@@ -274,6 +483,616 @@ pub fn file_exists(path: impl AsRef<Path>) -> bool {
     std::fs::metadata(path.as_ref()).map(|_| true).unwrap_or(false)
 }
 
+/// Recursively remove a directory tree without ever following a symlink.
+///
+/// A naive `remove_dir_all` over an attacker-influenced directory is
+/// vulnerable to the symlink-swap race fixed in Rust's std
+/// (CVE-2022-21658): a directory can be replaced with a symlink between the
+/// `read_dir` call and the recursive descent, causing deletion to escape the
+/// target tree. This classifies and descends into every entry relative to
+/// an already-open, `O_NOFOLLOW`-protected directory fd, so a swap after the
+/// fact is simply refused rather than followed.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn remove_dir_all(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> Result<()> {
+    reliable_remove_dir_all(path.as_ref(), base_dir.as_ref())
+        .await
+        .map_err(to_file_error)?;
+
+    Ok(())
+}
+
+async fn reliable_remove_dir_all(path: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(result) = dir_handle::remove_dir_all_at(path.as_ref(), base_dir.as_ref()).await {
+        return result;
+    }
+
+    // On Unix, reaching here means `path` wasn't a clean descendant of
+    // `base_dir` (e.g. it isn't UTF-8, or it escapes `base_dir` via `..`), so
+    // the openat-based fast path above returned `None`. The fallback below is
+    // std's path-based `remove_dir_all`: it does not descend into symlinks
+    // inside the tree, but it resolves `path` itself as a full path instead of
+    // walking down from `base_dir`, so make that visible.
+    #[cfg(unix)]
+    warn!(
+        "remove_dir_all: path {:?} is not a clean descendant of base_dir {:?}; falling back to the path-based \
+         implementation, which resolves the full path instead of walking from base_dir",
+        path.as_ref(),
+        base_dir.as_ref()
+    );
+
+    // Windows has no `*at` syscalls; fall back to the path-based std implementation.
+    fs::remove_dir_all(path.as_ref()).await
+}
+
+/// Directory-relative (`*at` family) syscall helpers.
+///
+/// Every helper in this file used to operate on full absolute paths, which
+/// means every call re-walks the whole path from the root and is vulnerable
+/// to a TOCTOU race: a component can be swapped for a symlink between the
+/// time a path is validated and the time it is used, redirecting the
+/// operation outside the intended tree. Holding an `O_DIRECTORY` file
+/// descriptor and resolving children relative to it (the same approach as
+/// the `openat` crate) closes that window, since each `*at` syscall is
+/// atomic with respect to the directory fd it targets.
+#[cfg(unix)]
+mod dir_handle {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+    use std::path::{Component, Path};
+
+    /// An open, `O_NOFOLLOW`-protected directory file descriptor. Children
+    /// are resolved with `openat`/`mkdirat`/`renameat`/`unlinkat` relative
+    /// to this fd, so a symlink swapped in for one of the already-resolved
+    /// path components cannot redirect later segments of the walk.
+    pub struct DirHandle {
+        fd: RawFd,
+    }
+
+    impl DirHandle {
+        /// Open `path` as a directory, refusing to follow a trailing symlink.
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: `c_path` is a valid, NUL-terminated C string for the
+            // duration of this call.
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd })
+        }
+
+        /// Open `name` relative to this directory, refusing to follow a
+        /// trailing symlink.
+        pub fn open_at(&self, name: &str) -> io::Result<Self> {
+            let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: `self.fd` is a valid, open directory fd and `c_name`
+            // is a valid, NUL-terminated C string for the duration of this call.
+            let fd = unsafe { libc::openat(self.fd, c_name.as_ptr(), libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd })
+        }
+
+        /// Create a directory named `name` inside this directory.
+        pub fn mkdir_at(&self, name: &str, mode: u32) -> io::Result<()> {
+            let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: `self.fd` is a valid, open directory fd and `c_name`
+            // is a valid, NUL-terminated C string for the duration of this call.
+            let ret = unsafe { libc::mkdirat(self.fd, c_name.as_ptr(), mode) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Rename `name` (a child of this directory) to `new_name` inside
+        /// `new_dir`, atomically with respect to both directory fds.
+        pub fn rename_at(&self, name: &str, new_dir: &DirHandle, new_name: &str) -> io::Result<()> {
+            let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let c_new_name = CString::new(new_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: both fds are valid, open directory fds and both C
+            // strings are valid and NUL-terminated for the duration of this call.
+            let ret = unsafe { libc::renameat(self.fd, c_name.as_ptr(), new_dir.fd, c_new_name.as_ptr()) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// List the names of this directory's entries, excluding `.` and `..`.
+        pub fn read_names(&self) -> io::Result<Vec<String>> {
+            // `fdopendir` takes ownership of the fd it's handed and closes it
+            // when the `DIR*` is closed, so hand it a dup'd copy: this
+            // `DirHandle` keeps owning (and closing) `self.fd` itself.
+            let dup_fd = unsafe { libc::dup(self.fd) };
+            if dup_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safety: `dup_fd` is a valid, just-duplicated directory fd.
+            let dirp = unsafe { libc::fdopendir(dup_fd) };
+            if dirp.is_null() {
+                let err = io::Error::last_os_error();
+                unsafe { libc::close(dup_fd) };
+                return Err(err);
+            }
+
+            let mut names = Vec::new();
+            loop {
+                // Safety: `dirp` is a valid, open `DIR*` for the duration of this loop.
+                let entry = unsafe { libc::readdir(dirp) };
+                if entry.is_null() {
+                    break;
+                }
+                // Safety: `d_name` is a NUL-terminated array for the lifetime of `entry`.
+                let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned();
+                if name != "." && name != ".." {
+                    names.push(name);
+                }
+            }
+            unsafe { libc::closedir(dirp) };
+            Ok(names)
+        }
+
+        /// `fstatat(AT_SYMLINK_NOFOLLOW)`: classify `name` without following
+        /// a trailing symlink.
+        pub fn stat_at(&self, name: &str) -> io::Result<libc::stat> {
+            let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mut st: libc::stat = unsafe { std::mem::zeroed() };
+            // Safety: `self.fd` is a valid, open directory fd, `c_name` is a
+            // valid C string, and `st` is a valid out-pointer for `fstatat`.
+            let ret = unsafe { libc::fstatat(self.fd, c_name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(st)
+        }
+
+        /// `unlinkat`: remove `name`. Pass `libc::AT_REMOVEDIR` in `flags` to
+        /// remove an (empty) subdirectory instead of a file.
+        pub fn unlink_at(&self, name: &str, flags: libc::c_int) -> io::Result<()> {
+            let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: `self.fd` is a valid, open directory fd and `c_name` is
+            // a valid, NUL-terminated C string for the duration of this call.
+            let ret = unsafe { libc::unlinkat(self.fd, c_name.as_ptr(), flags) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// `fsync` this directory's fd, forcing its entries to be persisted.
+        pub fn fsync(&self) -> io::Result<()> {
+            // Safety: `self.fd` is a valid, open directory fd.
+            let ret = unsafe { libc::fsync(self.fd) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for DirHandle {
+        fn drop(&mut self) {
+            // Safety: `self.fd` is only ever set by a successful `open`/`openat`
+            // above and is never shared, so closing it here is sound.
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+
+    /// Split `path` into the UTF-8 normal components of its path relative to
+    /// `base_dir`. Returns `None` if `path` does not live under `base_dir` or
+    /// contains a non-UTF-8 or non-normal (`.`, `..`, root) component, in
+    /// which case the caller should fall back to the path-based code.
+    fn relative_components(path: &Path, base_dir: &Path) -> Option<Vec<String>> {
+        let rel = path.strip_prefix(base_dir).ok()?;
+        let mut names = Vec::new();
+        for component in rel.components() {
+            match component {
+                Component::Normal(name) => names.push(name.to_str()?.to_string()),
+                _ => return None,
+            }
+        }
+        Some(names)
+    }
+
+    /// Open `base_dir` itself as a `DirHandle`.
+    ///
+    /// `base_dir` is a disk's configured root, not attacker-influenced
+    /// per-operation input, so resolving it to its canonical, symlink-free
+    /// form before opening is safe; it's what lets a disk whose root is
+    /// itself (or resolves through) a symlink keep using the fast,
+    /// `openat`-based path instead of failing with `ELOOP` on the plain
+    /// `O_NOFOLLOW` open.
+    fn open_base_dir(base_dir: &Path) -> io::Result<DirHandle> {
+        let canonical = std::fs::canonicalize(base_dir)?;
+        DirHandle::open(&canonical)
+    }
+
+    /// Open the directory handle for the parent of a path given as its
+    /// `base_dir`-relative `names`, walking from `base_dir` one `openat` per
+    /// segment, and return it together with the final file name.
+    fn open_parent_at(base_dir: &Path, names: &[String]) -> io::Result<(DirHandle, String)> {
+        let (name, parents) = names.split_last().expect("relative_components never returns an empty path");
+        let mut dir = open_base_dir(base_dir)?;
+        for parent in parents {
+            dir = dir.open_at(parent)?;
+        }
+        Ok((dir, name.clone()))
+    }
+
+    /// Race-free `rename(2)`: resolves the destination and source parent
+    /// directories by walking `base_dir` segment-by-segment with `openat`,
+    /// then renames with a single `renameat`. Returns `None` when the fast
+    /// path does not apply and the caller should fall back to the
+    /// path-based implementation.
+    pub async fn reliable_rename_at(
+        src_path: &Path,
+        dst_path: &Path,
+        base_dir: &Path,
+        max_retries: u32,
+    ) -> Option<io::Result<()>> {
+        let src_names = relative_components(src_path, base_dir)?;
+        let dst_names = relative_components(dst_path, base_dir)?;
+        if src_names.is_empty() || dst_names.is_empty() {
+            return None;
+        }
+
+        let base_dir = base_dir.to_path_buf();
+        let src_path = src_path.to_path_buf();
+        let dst_path = dst_path.to_path_buf();
+
+        let result = tokio::task::spawn_blocking(move || {
+            // Re-resolve both parent directory handles from `base_dir` on
+            // every attempt, same as the path-based fallback re-walks the
+            // path from scratch on every retry. NFS's longer retry budget
+            // exists to ride out transient ESTALE/EIO tied to a *stale*
+            // directory handle; looping `rename_at` on the exact same fds
+            // that produced the error would just reproduce it every time.
+            let mut i = 0;
+            loop {
+                let attempt = (|| -> io::Result<()> {
+                    let (src_dir, src_name) = open_parent_at(&base_dir, &src_names)?;
+                    let (dst_dir, dst_name) = open_parent_at(&base_dir, &dst_names)?;
+                    src_dir.rename_at(&src_name, &dst_dir, &dst_name)
+                })();
+
+                if let Err(e) = attempt {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        break;
+                    }
+                    if i < max_retries {
+                        i += 1;
+                        continue;
+                    }
+                    tracing::warn!(
+                        "reliable_rename failed. src_file_path: {:?}, dst_file_path: {:?}, base_dir: {:?}, err: {:?}",
+                        src_path,
+                        dst_path,
+                        base_dir,
+                        e
+                    );
+                    return Err(e);
+                }
+                break;
+            }
+            Ok(())
+        })
+        .await;
+
+        Some(match result {
+            Ok(r) => r,
+            Err(e) => Err(io::Error::other(e)),
+        })
+    }
+
+    /// Race-free `mkdir -p`: walks `base_dir` down to `path` one `openat`
+    /// per segment, creating any missing directory with `mkdirat` as it
+    /// goes, so no intermediate symlink swap can redirect the walk. Returns
+    /// `None` when the fast path does not apply and the caller should fall
+    /// back to the path-based implementation.
+    pub async fn reliable_mkdir_all_at(path: &Path, base_dir: &Path) -> Option<io::Result<()>> {
+        let names = relative_components(path, base_dir)?;
+        let base_dir = base_dir.to_path_buf();
+
+        let result = tokio::task::spawn_blocking(move || {
+            let mut dir = open_base_dir(&base_dir)?;
+            for name in &names {
+                match dir.mkdir_at(name, 0o777) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e),
+                }
+                dir = dir.open_at(name)?;
+            }
+            Ok(())
+        })
+        .await;
+
+        Some(match result {
+            Ok(r) => r,
+            Err(e) => Err(io::Error::other(e)),
+        })
+    }
+
+    /// Remove every entry inside `dir`, recursing into subdirectories by
+    /// re-opening them relative to `dir`'s fd (never by path), so a symlink
+    /// swapped in for a former subdirectory is refused rather than followed.
+    fn remove_dir_contents_at(dir: &DirHandle) -> io::Result<()> {
+        for name in dir.read_names()? {
+            let st = dir.stat_at(&name)?;
+            if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                // `open_at` requests `O_NOFOLLOW`: if this entry was swapped
+                // for a symlink since `stat_at` observed it as a directory,
+                // the open fails (ELOOP) instead of following it.
+                let child = dir.open_at(&name)?;
+                remove_dir_contents_at(&child)?;
+                drop(child);
+                dir.unlink_at(&name, libc::AT_REMOVEDIR)?;
+            } else {
+                dir.unlink_at(&name, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Race-free recursive `remove_dir_all`: walks `base_dir` down to
+    /// `path`'s parent with `openat`, then empties and removes `path` by
+    /// descending through directory fds rather than paths. Returns `None`
+    /// when the fast path does not apply and the caller should fall back to
+    /// the path-based implementation.
+    pub async fn remove_dir_all_at(path: &Path, base_dir: &Path) -> Option<io::Result<()>> {
+        let names = relative_components(path, base_dir)?;
+        if names.is_empty() {
+            return None;
+        }
+
+        let base_dir = base_dir.to_path_buf();
+
+        let result = tokio::task::spawn_blocking(move || {
+            let (parent_dir, name) = open_parent_at(&base_dir, &names)?;
+            let target = parent_dir.open_at(&name)?;
+            remove_dir_contents_at(&target)?;
+            drop(target);
+            parent_dir.unlink_at(&name, libc::AT_REMOVEDIR)
+        })
+        .await;
+
+        Some(match result {
+            Ok(r) => r,
+            Err(e) => Err(io::Error::other(e)),
+        })
+    }
+}
+
+/// Streaming recursive directory walker.
+///
+/// `read_dir` only lists a single level and materializes it into a `Vec`,
+/// which is awkward for healing/scanner passes that need to traverse an
+/// entire disk tree: every caller ends up hand-rolling its own stack of
+/// per-level `read_dir` loops. [`walk_dir`] gives those callers a single,
+/// lazy traversal primitive instead: it reads one directory level at a time
+/// (not the whole tree upfront) and lets the caller drive it with `.next()`.
+mod walk_dir {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// One entry yielded by [`WalkDir`].
+    #[derive(Debug, Clone)]
+    pub struct WalkEntry {
+        /// Path of this entry relative to the root passed to [`walk_dir`].
+        pub relative_path: PathBuf,
+        /// Depth below the root; the root's direct children are depth 1.
+        pub depth: usize,
+        /// This entry's file type. When `follow_symlinks` causes the walker
+        /// to descend into a symlinked directory, this is the *resolved*
+        /// (target) type rather than the raw symlink type, matching
+        /// `walkdir`'s convention: an entry whose subtree is being
+        /// traversed always reports as a directory.
+        pub file_type: std::fs::FileType,
+    }
+
+    struct DirFrame {
+        abs_path: PathBuf,
+        rel_path: PathBuf,
+        depth: usize,
+        entries: VecDeque<(String, std::fs::FileType)>,
+        // Canonical path of this directory, tracked only when following
+        // symlinks, so a cycle can be detected against the active ancestor chain.
+        canonical: Option<PathBuf>,
+    }
+
+    /// A lazy, depth-first directory walker. Build one with [`walk_dir`],
+    /// configure it with the builder methods, then drive it with
+    /// [`WalkDir::next`].
+    pub struct WalkDir {
+        root: PathBuf,
+        max_depth: Option<usize>,
+        min_depth: usize,
+        follow_symlinks: bool,
+        sorted: bool,
+        prune: Option<Arc<dyn Fn(&Path, &str) -> bool + Send + Sync>>,
+        stack: Vec<DirFrame>,
+        started: bool,
+    }
+
+    /// Build a [`WalkDir`] rooted at `path`. Does not follow symlinked
+    /// directories by default, to stay consistent with the rest of this module.
+    pub fn walk_dir(path: impl Into<PathBuf>) -> WalkDir {
+        WalkDir {
+            root: path.into(),
+            max_depth: None,
+            min_depth: 0,
+            follow_symlinks: false,
+            sorted: false,
+            prune: None,
+            stack: Vec::new(),
+            started: false,
+        }
+    }
+
+    impl WalkDir {
+        /// Only yield entries at most `max_depth` levels below the root.
+        pub fn max_depth(mut self, max_depth: usize) -> Self {
+            self.max_depth = Some(max_depth);
+            self
+        }
+
+        /// Only yield entries at least `min_depth` levels below the root.
+        pub fn min_depth(mut self, min_depth: usize) -> Self {
+            self.min_depth = min_depth;
+            self
+        }
+
+        /// Descend into symlinked directories instead of yielding them as leaves.
+        /// Cycles created this way are detected and broken.
+        pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+            self.follow_symlinks = follow_symlinks;
+            self
+        }
+
+        /// Yield each directory's entries in sorted-by-name order.
+        pub fn sorted(mut self, sorted: bool) -> Self {
+            self.sorted = sorted;
+            self
+        }
+
+        /// Skip descending into a directory entry when `prune` returns true
+        /// for its relative path and name (e.g. to skip UUID data
+        /// directories, see [`super::is_data_dir_name`]). The entry itself
+        /// is still yielded; only its subtree is pruned.
+        pub fn prune(mut self, prune: impl Fn(&Path, &str) -> bool + Send + Sync + 'static) -> Self {
+            self.prune = Some(Arc::new(prune));
+            self
+        }
+
+        async fn open_frame(&self, abs_path: &Path, rel_path: &Path, depth: usize, canonical: Option<PathBuf>) -> io::Result<DirFrame> {
+            let mut entries = Vec::new();
+            let mut rd = tokio::fs::read_dir(abs_path).await?;
+            while let Some(entry) = rd.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.is_empty() || name == "." || name == ".." {
+                    continue;
+                }
+                entries.push((name, entry.file_type().await?));
+            }
+            if self.sorted {
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+            Ok(DirFrame {
+                abs_path: abs_path.to_path_buf(),
+                rel_path: rel_path.to_path_buf(),
+                depth,
+                entries: entries.into(),
+                canonical,
+            })
+        }
+
+        /// Advance the walk, returning the next entry, or `None` once the
+        /// whole tree rooted at `path` has been visited.
+        pub async fn next(&mut self) -> Option<io::Result<WalkEntry>> {
+            if !self.started {
+                self.started = true;
+                let root = self.root.clone();
+                // Seed the root frame's canonical path exactly like every other
+                // frame gets: otherwise a symlink anywhere in the tree that
+                // resolves back to the root is never compared against it (the
+                // cycle check only looks at frames that have a `canonical`),
+                // so the whole tree gets traversed a second time before the
+                // cycle is caught one level later by accident.
+                let canonical = if self.follow_symlinks {
+                    match tokio::fs::canonicalize(&root).await {
+                        Ok(c) => Some(c),
+                        Err(e) => return Some(Err(e)),
+                    }
+                } else {
+                    None
+                };
+                match self.open_frame(&root, Path::new(""), 0, canonical).await {
+                    Ok(frame) => self.stack.push(frame),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            loop {
+                let Some(frame) = self.stack.last_mut() else {
+                    return None;
+                };
+                let Some((name, file_type)) = frame.entries.pop_front() else {
+                    self.stack.pop();
+                    continue;
+                };
+
+                let depth = frame.depth + 1;
+                let abs_path = frame.abs_path.join(&name);
+                let rel_path = frame.rel_path.join(&name);
+
+                let mut reported_file_type = file_type;
+                let is_dir = if file_type.is_symlink() {
+                    if self.follow_symlinks {
+                        match tokio::fs::metadata(&abs_path).await {
+                            Ok(meta) => {
+                                // Report the resolved type: when the target is a
+                                // directory the walker may recurse into it, so callers
+                                // branching on `file_type` should see a directory.
+                                reported_file_type = meta.file_type();
+                                meta.is_dir()
+                            }
+                            Err(e) => return Some(Err(e)),
+                        }
+                    } else {
+                        false
+                    }
+                } else {
+                    file_type.is_dir()
+                };
+
+                let pruned = self.prune.as_ref().is_some_and(|prune| prune(&rel_path, &name));
+                let within_max_depth = self.max_depth.map(|max_depth| depth < max_depth).unwrap_or(true);
+
+                if is_dir && !pruned && within_max_depth {
+                    let mut canonical = None;
+                    let mut cyclic = false;
+                    if self.follow_symlinks {
+                        match tokio::fs::canonicalize(&abs_path).await {
+                            Ok(c) => {
+                                cyclic = self.stack.iter().filter_map(|f| f.canonical.as_ref()).any(|ancestor| ancestor == &c);
+                                canonical = Some(c);
+                            }
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+
+                    if !cyclic {
+                        match self.open_frame(&abs_path, &rel_path, depth, canonical).await {
+                            Ok(child) => self.stack.push(child),
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+                }
+
+                if depth >= self.min_depth && self.max_depth.map(|max_depth| depth <= max_depth).unwrap_or(true) {
+                    return Some(Ok(WalkEntry {
+                        relative_path: rel_path,
+                        depth,
+                        file_type: reported_file_type,
+                    }));
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -371,4 +1190,233 @@ mod tests {
             "Directory with non-UUID subdirectory should return false"
         );
     }
+
+    /// `rename_all` must create missing destination parents and move the
+    /// file via the openat-based fast path.
+    #[tokio::test]
+    async fn test_rename_all_moves_file_across_new_parent_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        let src = base_path.join("src.txt");
+        fs::write(&src, b"payload").await.unwrap();
+
+        let dst = base_path.join("nested").join("deeper").join("dst.txt");
+        rename_all(&src, &dst, base_path, false).await.unwrap();
+
+        assert!(!src.exists(), "source file should be gone after rename");
+        assert_eq!(fs::read(&dst).await.unwrap(), b"payload");
+    }
+
+    /// A disk whose `base_dir` itself is a symlink must still work through
+    /// the fast, openat-based path instead of failing with `ELOOP`.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rename_all_with_symlinked_base_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let real_base = temp_dir.path().join("real_base");
+        fs::create_dir(&real_base).await.unwrap();
+        let linked_base = temp_dir.path().join("linked_base");
+        std::os::unix::fs::symlink(&real_base, &linked_base).unwrap();
+
+        let src = linked_base.join("src.txt");
+        fs::write(&src, b"payload").await.unwrap();
+        let dst = linked_base.join("dst.txt");
+
+        rename_all(&src, &dst, &linked_base, false).await.unwrap();
+
+        assert!(!real_base.join("src.txt").exists());
+        assert_eq!(fs::read(real_base.join("dst.txt")).await.unwrap(), b"payload");
+    }
+
+    /// `remove_dir_all` must refuse to follow a symlink found inside the
+    /// tree being removed (CVE-2022-21658).
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_remove_dir_all_refuses_to_follow_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        // A directory outside the tree we're about to delete.
+        let outside = base_path.join("outside");
+        fs::create_dir(&outside).await.unwrap();
+        fs::write(outside.join("keep.txt"), "keep me").await.unwrap();
+
+        // The tree we ask to delete contains a symlink pointing at `outside`.
+        let victim = base_path.join("victim");
+        fs::create_dir(&victim).await.unwrap();
+        std::os::unix::fs::symlink(&outside, victim.join("escape")).unwrap();
+
+        remove_dir_all(&victim, base_path).await.unwrap();
+
+        assert!(!victim.exists(), "victim directory should be removed");
+        assert!(
+            outside.join("keep.txt").exists(),
+            "remove_dir_all must not follow the symlink into `outside`"
+        );
+    }
+
+    /// `rename_all(durable: true)` fsyncs both parent directories after the
+    /// rename; it must still complete the move correctly.
+    #[tokio::test]
+    async fn test_rename_all_durable_moves_file_across_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        let src_dir = base_path.join("src_dir");
+        let dst_dir = base_path.join("dst_dir");
+        fs::create_dir(&src_dir).await.unwrap();
+        fs::create_dir(&dst_dir).await.unwrap();
+
+        let src = src_dir.join("object");
+        let dst = dst_dir.join("object");
+        fs::write(&src, b"data").await.unwrap();
+
+        rename_all(&src, &dst, base_path, true).await.unwrap();
+
+        assert!(!src.exists(), "source file should be gone after rename");
+        assert_eq!(fs::read(&dst).await.unwrap(), b"data");
+    }
+
+    /// `walk_dir` visits entries depth-first, yielding a directory before its
+    /// children, and (with `sorted`) in name order within each level.
+    #[tokio::test]
+    async fn test_walk_dir_depth_and_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        fs::create_dir_all(base_path.join("a/b")).await.unwrap();
+        fs::write(base_path.join("a/file1.txt"), "1").await.unwrap();
+        fs::write(base_path.join("a/b/file2.txt"), "2").await.unwrap();
+
+        let mut walker = walk_dir(base_path).sorted(true);
+        let mut seen = Vec::new();
+        while let Some(entry) = walker.next().await {
+            let entry = entry.unwrap();
+            seen.push((entry.relative_path, entry.depth));
+        }
+
+        assert_eq!(
+            seen,
+            vec![
+                (std::path::PathBuf::from("a"), 1),
+                (std::path::PathBuf::from("a/b"), 2),
+                (std::path::PathBuf::from("a/b/file2.txt"), 3),
+                (std::path::PathBuf::from("a/file1.txt"), 2),
+            ]
+        );
+    }
+
+    /// `prune` stops descent into a directory's subtree but still yields the
+    /// directory entry itself.
+    #[tokio::test]
+    async fn test_walk_dir_prune_skips_subtree_but_yields_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        let uuid_dir = base_path.join("550e8400-e29b-41d4-a716-446655440000");
+        fs::create_dir(&uuid_dir).await.unwrap();
+        fs::write(uuid_dir.join("part.1"), "x").await.unwrap();
+        fs::write(base_path.join("xl.meta"), "meta").await.unwrap();
+
+        let mut walker = walk_dir(base_path).sorted(true).prune(|_, name| is_data_dir_name(name));
+
+        let mut relative_paths = Vec::new();
+        while let Some(entry) = walker.next().await {
+            relative_paths.push(entry.unwrap().relative_path);
+        }
+
+        assert!(relative_paths.contains(&std::path::PathBuf::from("550e8400-e29b-41d4-a716-446655440000")));
+        assert!(
+            !relative_paths.iter().any(|p| p.ends_with("part.1")),
+            "pruned subtree's contents must not be yielded"
+        );
+        assert!(relative_paths.contains(&std::path::PathBuf::from("xl.meta")));
+    }
+
+    /// A symlink cycle must not send `walk_dir` into an infinite loop when
+    /// following symlinks.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_walk_dir_detects_symlink_cycle() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        let a = base_path.join("a");
+        fs::create_dir(&a).await.unwrap();
+        std::os::unix::fs::symlink(base_path, a.join("loop")).unwrap();
+
+        let mut walker = walk_dir(base_path).follow_symlinks(true).max_depth(10);
+        let mut count = 0;
+        while let Some(entry) = walker.next().await {
+            entry.unwrap();
+            count += 1;
+            assert!(count < 100, "walk_dir should break symlink cycles instead of looping");
+        }
+    }
+
+    /// A symlink pointing directly at the walk root (not a nested ancestor)
+    /// must also be caught as a cycle, instead of the walker re-traversing
+    /// the whole tree once before a deeper frame happens to catch it.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_walk_dir_detects_symlink_cycle_to_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        for i in 0..20 {
+            fs::write(base_path.join(format!("file{i}.txt")), "x").await.unwrap();
+        }
+        std::os::unix::fs::symlink(base_path, base_path.join("self_loop")).unwrap();
+
+        let mut walker = walk_dir(base_path).follow_symlinks(true).max_depth(10);
+        let mut paths = Vec::new();
+        while let Some(entry) = walker.next().await {
+            let entry = entry.unwrap();
+            paths.push(entry.relative_path);
+            assert!(
+                paths.len() < 200,
+                "walk_dir should break a symlink cycle pointing at the root instead of re-traversing it"
+            );
+        }
+
+        let file0_count = paths.iter().filter(|p| *p == std::path::Path::new("file0.txt")).count();
+        assert_eq!(
+            file0_count, 1,
+            "a root-pointing symlink must not cause the root's own contents to be revisited"
+        );
+    }
+
+    /// When following symlinks into a directory, the yielded entry reports
+    /// the resolved (directory) type, not the raw symlink type.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_walk_dir_reports_resolved_type_for_followed_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let base_path = temp_dir.path();
+
+        let real_dir = base_path.join("real_dir");
+        fs::create_dir(&real_dir).await.unwrap();
+        fs::write(real_dir.join("inner.txt"), "x").await.unwrap();
+        std::os::unix::fs::symlink(&real_dir, base_path.join("link_dir")).unwrap();
+
+        let mut walker = walk_dir(base_path).sorted(true).follow_symlinks(true);
+        let mut entries = Vec::new();
+        while let Some(entry) = walker.next().await {
+            entries.push(entry.unwrap());
+        }
+
+        let link_entry = entries
+            .iter()
+            .find(|e| e.relative_path == std::path::PathBuf::from("link_dir"))
+            .unwrap();
+        assert!(
+            link_entry.file_type.is_dir(),
+            "a followed symlink's entry should report the resolved directory type"
+        );
+        assert!(
+            entries.iter().any(|e| e.relative_path == std::path::PathBuf::from("link_dir/inner.txt")),
+            "walker should have descended into the symlinked directory"
+        );
+    }
 }